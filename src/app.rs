@@ -1,17 +1,38 @@
 use std::{
-    io::{Seek, Write},
+    io::Write,
     path::{Path, PathBuf},
 };
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use image::{imageops::FilterType, GenericImageView};
-use rayon::prelude::*;
 
+use crate::archive;
+use crate::decode;
+use crate::format::OutputFormat;
+use crate::serve;
 use crate::zip_util;
 
 #[derive(Parser, Debug)]
-struct Args {
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// zipファイル内の画像をリサイズする
+    Run(RunArgs),
+
+    /// zipファイル内の画像をリサイズしながらHTTP経由で配信する
+    Serve(serve::ServeArgs),
+
+    /// `--format` に指定できる出力形式の一覧を表示する
+    ListFormats,
+}
+
+#[derive(Parser, Debug)]
+struct RunArgs {
     /// 出力先ディレクトリ
     #[clap(short = 'd', long)]
     output_dir: Option<PathBuf>,
@@ -24,10 +45,62 @@ struct Args {
     #[clap(short, long)]
     keep_mtime: bool,
 
+    /// 読み込むzipファイルのパスワード (暗号化されている場合)
+    #[clap(long)]
+    password: Option<String>,
+
+    /// 出力するzipファイルのパスワード (指定するとAES-256で暗号化する)
+    #[clap(long = "out-password")]
+    out_password: Option<String>,
+
+    /// 画像以外のエントリに使う圧縮方式
+    #[clap(long, value_enum, default_value_t = CompressionMethod::Deflate)]
+    compression: CompressionMethod,
+
+    /// 圧縮レベル (対応する圧縮方式でのみ有効)
+    #[clap(long)]
+    compression_level: Option<i32>,
+
+    /// 変換後の画像形式
+    #[clap(long, value_enum, default_value_t = OutputFormat::Webp)]
+    format: OutputFormat,
+
     zipfiles: Vec<PathBuf>,
 }
 
-trait ReduceSize {
+/// 画像以外のエントリに使う圧縮方式。画像エントリは再圧縮の恩恵がないため
+/// 常にStoredで書き込まれる。
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum CompressionMethod {
+    Stored,
+    Deflate,
+    Bzip2,
+    Zstd,
+}
+
+impl From<CompressionMethod> for async_zip::Compression {
+    fn from(method: CompressionMethod) -> Self {
+        match method {
+            CompressionMethod::Stored => async_zip::Compression::Stored,
+            CompressionMethod::Deflate => async_zip::Compression::Deflate,
+            CompressionMethod::Bzip2 => async_zip::Compression::Bz,
+            CompressionMethod::Zstd => async_zip::Compression::Zstd,
+        }
+    }
+}
+
+impl From<CompressionMethod> for zip::CompressionMethod {
+    fn from(method: CompressionMethod) -> Self {
+        match method {
+            CompressionMethod::Stored => zip::CompressionMethod::Stored,
+            CompressionMethod::Deflate => zip::CompressionMethod::Deflated,
+            CompressionMethod::Bzip2 => zip::CompressionMethod::Bzip2,
+            CompressionMethod::Zstd => zip::CompressionMethod::Zstd,
+        }
+    }
+}
+
+pub(crate) trait ReduceSize {
     fn reduce_size(self, height: u32) -> Self;
 }
 
@@ -40,109 +113,112 @@ impl ReduceSize for image::DynamicImage {
     }
 }
 
-#[allow(dead_code)]
-fn resize_image_file_jpg<P: AsRef<Path>>(path: P, min_height: u32) -> image::ImageResult<()> {
-    let Ok(image) = image::open(path.as_ref()) else {
-        return Ok(());
-    };
-
-    println!("resize image: {:?}", path.as_ref());
-    std::fs::remove_file(path.as_ref())?;
-    let resized_image = image.reduce_size(min_height);
-
-    let writer =
-        std::io::BufWriter::new(std::fs::File::create(path.as_ref().with_extension("jpg"))?);
-    let mut enc = image::codecs::jpeg::JpegEncoder::new_with_quality(writer, 95);
-
-    // エンコード（ファイルへの書き込み）
-    enc.encode(
-        resized_image.as_bytes(),
-        resized_image.width(),
-        resized_image.height(),
-        resized_image.color(),
-    )
+const MAX_ENTRY_BYTES: usize = 1024 * 1024;
 
-    // resized_image.save(path.as_ref().with_extension("jpg"))
+/// アーカイブエントリ1件分の変換を行う。画像であれば縮小・指定形式へエンコードして
+/// 差し替え、それ以外や既に十分小さい同形式の画像はそのまま通過させる。
+///
+/// 画像処理はCPUバウンドなので `spawn_blocking` でブロッキングスレッドに逃がし、
+/// 呼び出し側の `concurrency` によって同時実行数を絞る。
+async fn transform_entry(
+    filename: String,
+    data: Vec<u8>,
+    min_height: u32,
+    format: OutputFormat,
+) -> Result<zip_util::EntryOutcome> {
+    tokio::task::spawn_blocking(move || transform_entry_blocking(filename, data, min_height, format))
+        .await?
 }
 
-fn resize_image_file_webp<P: AsRef<Path>>(path: P, min_height: u32) -> Result<bool> {
-    if image::guess_format(&std::fs::read(path.as_ref())?)? == image::ImageFormat::WebP
-        && path.as_ref().metadata()?.len() < 1024 * 1024
-    {
-        return Ok(false);
+fn transform_entry_blocking(
+    filename: String,
+    data: Vec<u8>,
+    min_height: u32,
+    format: OutputFormat,
+) -> Result<zip_util::EntryOutcome> {
+    let already_matches = image::guess_format(&data)
+        .map(|guessed| format.matches(guessed))
+        .unwrap_or(false);
+    if already_matches && data.len() < MAX_ENTRY_BYTES {
+        return Ok(zip_util::EntryOutcome::PassThrough(data));
     }
 
-    let Ok(image) = image::open(path.as_ref()) else {
-        return Ok(false);
+    let Some(image) = decode::decode_image(&filename, &data, min_height) else {
+        return Ok(zip_util::EntryOutcome::PassThrough(data));
     };
 
-    println!("resize image: {:?}", path.as_ref());
-    std::fs::remove_file(path.as_ref())?;
-
-    // Convert to supported format by webp encoder
-    let image = match image {
-        image::DynamicImage::ImageRgb8(_) => image,
-        image::DynamicImage::ImageRgba8(_) => image,
-        _ => image::DynamicImage::ImageRgb8(image.to_rgb8()),
-    };
+    println!("resize image: {filename}");
 
     let reduced_image = image.reduce_size(min_height);
-
-    let encoder = match webp::Encoder::from_image(&reduced_image) {
-        Ok(encoder) => encoder,
-        Err(e) => {
-            anyhow::bail!("failed to create webp encoder: {e}");
-        }
-    };
-
-    let webp = (15..=95)
-        .rev()
-        .step_by(10)
-        .map(|quality| encoder.encode(quality as f32))
-        .find(|webp| webp.len() < 1024 * 1024)
-        .unwrap_or_else(|| encoder.encode(10.0));
-
-    let file = std::fs::File::create(path.as_ref().with_extension("webp"))?;
-    let mut writer = std::io::BufWriter::new(file);
-    writer.write_all(&webp)?;
-    writer.flush()?;
-
-    Ok(true)
+    let encoded = format.encode_under_size(&reduced_image, MAX_ENTRY_BYTES)?;
+
+    Ok(zip_util::EntryOutcome::Replace {
+        filename: Path::new(&filename)
+            .with_extension(format.extension())
+            .to_string_lossy()
+            .into_owned(),
+        bytes: encoded,
+    })
 }
 
-fn resize_image_zipfile<P1: AsRef<Path>, P2: AsRef<Path>>(
+#[allow(clippy::too_many_arguments)]
+async fn resize_image_zipfile<P1: AsRef<Path>, P2: AsRef<Path>>(
     src_zipfile: P1,
     dst_zipfile: P2,
     min_height: u32,
     keep_mtime: bool,
+    password: Option<String>,
+    out_password: Option<String>,
+    compression: CompressionMethod,
+    compression_level: Option<i32>,
+    format: OutputFormat,
 ) -> Result<()> {
-    let work_dir = tempfile::tempdir()?;
-    zip_util::unzip(&src_zipfile, work_dir.path())?;
-
-    let files = walkdir::WalkDir::new(work_dir.path())
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .collect::<Vec<_>>();
-
-    let resized_count = files
-        .par_iter()
-        .map(|file| resize_image_file_webp(file.path(), min_height))
-        .filter(|r| *r.as_ref().ok().unwrap_or(&false))
-        .collect::<Result<Vec<_>>>()?
-        .len();
+    let concurrency = std::thread::available_parallelism()
+        .map(std::num::NonZeroUsize::get)
+        .unwrap_or(4);
+
+    let output_compression = zip_util::OutputCompression {
+        method: compression.into(),
+        level: compression_level,
+    };
+
+    // 出力が暗号化される場合は、一度平文のzipとして書き出してからAES暗号化する
+    // 必要があるため、パスが取得できるNamedTempFileを使う。
+    let plain_tempfile = tempfile::NamedTempFile::new()?;
+    let dst_tokio_file = tokio::fs::File::from_std(plain_tempfile.reopen()?);
+
+    let resized_count = zip_util::stream_entries(
+        &src_zipfile,
+        dst_tokio_file,
+        concurrency,
+        password,
+        output_compression,
+        move |filename, data| transform_entry(filename, data, min_height, format),
+    )
+    .await?;
 
     if resized_count == 0 {
         return Ok(());
     }
 
-    let mut dst_tempfile = tempfile::tempfile()?;
-    zip_util::zip(work_dir.path(), &dst_tempfile)?;
-
-    // dst_tempfile -> dst_zipfile
-    dst_tempfile.seek(std::io::SeekFrom::Start(0))?;
-    let mut writer = std::fs::File::create(dst_zipfile.as_ref())?;
-    std::io::copy(&mut dst_tempfile, &mut writer)?;
+    let writer = match out_password {
+        Some(out_password) => {
+            let writer = std::fs::File::create(dst_zipfile.as_ref())?;
+            zip_util::encrypt_zip(
+                plain_tempfile.path(),
+                &writer,
+                &out_password,
+                compression_level,
+            )?;
+            writer
+        }
+        None => {
+            let mut plain = plain_tempfile.reopen()?;
+            let mut writer = std::fs::File::create(dst_zipfile.as_ref())?;
+            std::io::copy(&mut plain, &mut writer)?;
+            writer
+        }
+    };
 
     if keep_mtime {
         let src_mtime = src_zipfile.as_ref().metadata()?.modified()?;
@@ -152,6 +228,83 @@ fn resize_image_zipfile<P1: AsRef<Path>, P2: AsRef<Path>>(
     Ok(())
 }
 
+/// ZIP以外のコンテナ (CBR/RAR, 7z, tar) を読み込み、画像を変換したうえで
+/// ZIP (CBZ)へ正規化して書き出す。これらの形式は`zip_util::stream_entries`の
+/// ようなストリーミング書き込みに対応していないため、全エントリを読み込んでから
+/// まとめて書き出す。
+#[allow(clippy::too_many_arguments)]
+async fn resize_non_zip_archive<P: AsRef<Path>>(
+    src_file: P,
+    dst_file: &Path,
+    kind: archive::ArchiveKind,
+    min_height: u32,
+    keep_mtime: bool,
+    out_password: Option<String>,
+    compression: CompressionMethod,
+    compression_level: Option<i32>,
+    format: OutputFormat,
+) -> Result<()> {
+    let src_path = src_file.as_ref().to_path_buf();
+    let entries = tokio::task::spawn_blocking({
+        let src_path = src_path.clone();
+        move || archive::open_reader(&src_path, kind)?.read_all()
+    })
+    .await??;
+
+    use futures::{StreamExt, TryStreamExt};
+
+    let concurrency = std::thread::available_parallelism()
+        .map(std::num::NonZeroUsize::get)
+        .unwrap_or(4);
+
+    let transforms = entries.into_iter().map(|(filename, data)| async move {
+        let outcome = transform_entry(filename.clone(), data, min_height, format).await?;
+        Ok::<_, anyhow::Error>((filename, outcome))
+    });
+    let outcomes: Vec<(String, zip_util::EntryOutcome)> = futures::stream::iter(transforms)
+        .buffered(concurrency)
+        .try_collect()
+        .await?;
+
+    let dst = std::fs::File::create(dst_file)?;
+    let mut writer = zip::ZipWriter::new(dst);
+
+    for (original_filename, outcome) in outcomes {
+        let (filename, bytes, is_image) = match outcome {
+            zip_util::EntryOutcome::Replace { filename, bytes } => (filename, bytes, true),
+            zip_util::EntryOutcome::PassThrough(bytes) => {
+                let is_image = zip_util::is_image_extension(&original_filename);
+                (original_filename, bytes, is_image)
+            }
+        };
+
+        let method = if is_image {
+            zip::CompressionMethod::Stored
+        } else {
+            compression.into()
+        };
+
+        let mut options = zip::write::FileOptions::default()
+            .compression_method(method)
+            .compression_level(compression_level.map(i64::from));
+        if let Some(password) = &out_password {
+            options = options.with_aes_encryption(zip::AesMode::Aes256, password);
+        }
+
+        writer.start_file(filename, options)?;
+        writer.write_all(&bytes)?;
+    }
+
+    let file = writer.finish()?;
+
+    if keep_mtime {
+        let src_mtime = src_path.metadata()?.modified()?;
+        file.set_modified(src_mtime)?;
+    }
+
+    Ok(())
+}
+
 fn append_suffix_to_filename<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
     let path = path.as_ref();
     let mut stem = path.file_stem().unwrap().to_os_string();
@@ -161,20 +314,31 @@ fn append_suffix_to_filename<P: AsRef<Path>>(path: P, suffix: &str) -> PathBuf {
     path.with_file_name(stem).with_extension(ext)
 }
 
-fn determine_output_path(path: &Path, output_dir: &Option<PathBuf>) -> PathBuf {
+fn determine_output_path(
+    path: &Path,
+    output_dir: &Option<PathBuf>,
+    kind: archive::ArchiveKind,
+) -> PathBuf {
+    // ZIP以外のコンテナはCBZへ正規化して書き出すため、出力の拡張子もそれに揃える
+    let path = match kind {
+        archive::ArchiveKind::Zip => path.to_path_buf(),
+        _ => path.with_extension("cbz"),
+    };
+
     match output_dir {
         Some(output_dir) => output_dir.join(path.file_name().unwrap()),
-        None => append_suffix_to_filename(path, "_resized"),
+        None => append_suffix_to_filename(&path, "_resized"),
     }
 }
 
-fn calc_average_size_per_file<P: AsRef<Path>>(path: P) -> Result<u64> {
+fn calc_average_size_per_file<P: AsRef<Path>>(path: P, password: Option<&str>) -> Result<u64> {
     let file_size = path.as_ref().metadata()?.len();
-    let file_count = zip_util::get_file_count(path)? as u64;
+    let kind = archive::ArchiveKind::sniff(path.as_ref())?;
+    let file_count = archive::get_file_count(path.as_ref(), kind, password)? as u64;
     Ok(file_size / file_count)
 }
 
-fn print_error(mut err: &dyn std::error::Error) {
+pub(crate) fn print_error(mut err: &dyn std::error::Error) {
     let _ = writeln!(std::io::stderr(), "error: {}", err);
     while let Some(source) = err.source() {
         let _ = writeln!(std::io::stderr(), "caused by: {}", source);
@@ -183,19 +347,86 @@ fn print_error(mut err: &dyn std::error::Error) {
 }
 
 pub fn run() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Run(args) => run_resize(args),
+        Command::Serve(args) => {
+            if let Err(err) = serve::run(args) {
+                print_error(err.as_ref());
+                std::process::exit(1);
+            }
+        }
+        Command::ListFormats => {
+            for format in OutputFormat::ALL {
+                println!("{format}");
+            }
+        }
+    }
+}
 
-    let (convert_files, _): (Vec<_>, Vec<_>) = args
-        .zipfiles
-        .iter()
-        .partition(|f| calc_average_size_per_file(f).unwrap_or(0) > 2 * 1024);
+fn run_resize(args: RunArgs) {
+    let mut convert_files = Vec::new();
+    for zipfile in &args.zipfiles {
+        match calc_average_size_per_file(zipfile, args.password.as_deref()) {
+            Ok(average_size) => {
+                if average_size > 2 * 1024 {
+                    convert_files.push(zipfile);
+                }
+            }
+            // パスワード誤りや未対応フォーマットなど、ここで検出されたエラーを
+            // "リサイズ不要"として握りつぶすとユーザーに気付かれず処理が終わって
+            // しまうため、明示的に報告してこのファイルのみスキップする。
+            Err(err) => print_error(err.as_ref()),
+        }
+    }
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            print_error(&err);
+            std::process::exit(1);
+        }
+    };
 
     for zipfile in convert_files {
-        let dst_zipfile = determine_output_path(zipfile, &args.output_dir);
+        let kind = match archive::ArchiveKind::sniff(zipfile) {
+            Ok(kind) => kind,
+            Err(err) => {
+                print_error(err.as_ref());
+                continue;
+            }
+        };
+
+        let dst_zipfile = determine_output_path(zipfile, &args.output_dir, kind);
         println!("resizing zipfile: {:?} -> {:?}", zipfile, dst_zipfile);
-        if let Err(err) =
-            resize_image_zipfile(zipfile, dst_zipfile, args.min_height, args.keep_mtime)
-        {
+
+        let result = match kind {
+            archive::ArchiveKind::Zip => runtime.block_on(resize_image_zipfile(
+                zipfile,
+                dst_zipfile,
+                args.min_height,
+                args.keep_mtime,
+                args.password.clone(),
+                args.out_password.clone(),
+                args.compression,
+                args.compression_level,
+                args.format,
+            )),
+            _ => runtime.block_on(resize_non_zip_archive(
+                zipfile,
+                &dst_zipfile,
+                kind,
+                args.min_height,
+                args.keep_mtime,
+                args.out_password.clone(),
+                args.compression,
+                args.compression_level,
+                args.format,
+            )),
+        };
+
+        if let Err(err) = result {
             print_error(err.as_ref());
             std::process::exit(1);
         }
@@ -0,0 +1,264 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::zip_util;
+
+/// ソースアーカイブの種類。拡張子ではなく先頭のマジックバイトから判定する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Rar,
+    SevenZip,
+    Tar,
+}
+
+impl ArchiveKind {
+    /// ファイル先頭のバイト列からアーカイブの種類を判定する
+    pub fn sniff<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut header = [0u8; 512];
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+        let n = file.read(&mut header)?;
+        let header = &header[..n];
+
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Ok(ArchiveKind::Zip)
+        } else if header.starts_with(b"Rar!\x1a\x07") {
+            Ok(ArchiveKind::Rar)
+        } else if header.starts_with(b"7z\xbc\xaf\x27\x1c") {
+            Ok(ArchiveKind::SevenZip)
+        } else if header.len() > 262 && &header[257..262] == b"ustar" {
+            Ok(ArchiveKind::Tar)
+        } else {
+            anyhow::bail!("unrecognized archive format: {path:?}")
+        }
+    }
+}
+
+/// ZIP以外のコンテナ形式から画像エントリを読み出すための最小限の抽象。
+/// これらの形式はストリーミング書き込みに対応していないため、出力は常に
+/// ZIP (CBZ)へ正規化する。
+pub trait ArchiveReader {
+    /// アーカイブに含まれるファイル数を取得する。ヘッダーのみを走査し、
+    /// エントリ本体の展開は行わない。
+    fn file_count(&mut self) -> Result<usize>;
+
+    /// ディレクトリを除く全エントリを名前付きで読み出す
+    fn read_all(&mut self) -> Result<Vec<(String, Vec<u8>)>>;
+}
+
+/// `kind` に応じたリーダーを開く。`Zip`は`zip_util`/`stream_entries`経由で
+/// 直接扱うため、ここでは非対応としている。
+pub fn open_reader(path: &Path, kind: ArchiveKind) -> Result<Box<dyn ArchiveReader>> {
+    match kind {
+        ArchiveKind::Zip => anyhow::bail!("zip archives should be read through zip_util"),
+        ArchiveKind::Rar => Ok(Box::new(RarReader {
+            path: path.to_path_buf(),
+        })),
+        ArchiveKind::SevenZip => Ok(Box::new(SevenZipReader {
+            path: path.to_path_buf(),
+        })),
+        ArchiveKind::Tar => Ok(Box::new(TarReader {
+            path: path.to_path_buf(),
+        })),
+    }
+}
+
+/// コンテナ種別を問わずアーカイブ内のファイル数を取得する。
+/// `calc_average_size_per_file`の「リサイズする価値があるか」のヒューリスティックに使う。
+///
+/// このヒューリスティックのためだけに全エントリを展開するのは無駄なので、
+/// `ArchiveReader::file_count`を使いヘッダーの走査のみで済ませる。
+pub fn get_file_count(path: &Path, kind: ArchiveKind, password: Option<&str>) -> Result<usize> {
+    match kind {
+        ArchiveKind::Zip => zip_util::get_file_count(path, password),
+        _ => {
+            if password.is_some() {
+                anyhow::bail!("--password is only supported for ZIP archives");
+            }
+            open_reader(path, kind)?.file_count()
+        }
+    }
+}
+
+struct RarReader {
+    path: PathBuf,
+}
+
+impl ArchiveReader for RarReader {
+    fn file_count(&mut self) -> Result<usize> {
+        // ヘッダーのみを走査するリスティングモードで開くため、エントリ本体は展開されない
+        let archive = unrar::Archive::new(&self.path)
+            .open_for_listing()
+            .with_context(|| format!("failed to open RAR archive {:?}", self.path))?;
+
+        let mut count = 0;
+        for header in archive {
+            if !header?.is_directory() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = Vec::new();
+
+        let archive = unrar::Archive::new(&self.path)
+            .open_for_processing()
+            .with_context(|| format!("failed to open RAR archive {:?}", self.path))?;
+        let mut cursor = Some(archive);
+
+        while let Some(archive) = cursor {
+            cursor = match archive.read_header()? {
+                Some(header) => {
+                    if header.entry().is_directory() {
+                        Some(header.skip()?)
+                    } else {
+                        let name = header.entry().filename.to_string_lossy().into_owned();
+                        let (data, next) = header.read()?;
+                        entries.push((name, data));
+                        Some(next)
+                    }
+                }
+                None => None,
+            };
+        }
+
+        Ok(entries)
+    }
+}
+
+struct SevenZipReader {
+    path: PathBuf,
+}
+
+impl ArchiveReader for SevenZipReader {
+    fn file_count(&mut self) -> Result<usize> {
+        // アーカイブのヘッダーに含まれるエントリ一覧だけを見るため、展開は発生しない
+        let reader = sevenz_rust::SevenZReader::open(&self.path, sevenz_rust::Password::empty())
+            .with_context(|| format!("failed to open 7z archive {:?}", self.path))?;
+
+        Ok(reader
+            .archive()
+            .files
+            .iter()
+            .filter(|entry| !entry.is_directory())
+            .count())
+    }
+
+    fn read_all(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = Vec::new();
+
+        let mut reader = sevenz_rust::SevenZReader::open(&self.path, sevenz_rust::Password::empty())
+            .with_context(|| format!("failed to open 7z archive {:?}", self.path))?;
+        reader.for_each_entries(|entry, reader| {
+            if !entry.is_directory() {
+                let mut buf = Vec::with_capacity(entry.size() as usize);
+                reader.read_to_end(&mut buf)?;
+                entries.push((entry.name().to_owned(), buf));
+            }
+            Ok(true)
+        })?;
+
+        Ok(entries)
+    }
+}
+
+struct TarReader {
+    path: PathBuf,
+}
+
+impl ArchiveReader for TarReader {
+    fn file_count(&mut self) -> Result<usize> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("failed to open tar archive {:?}", self.path))?;
+        let mut archive = tar::Archive::new(file);
+
+        // エントリ本体は読み飛ばし、ヘッダーの種別だけを見て数える
+        let mut count = 0;
+        for entry in archive.entries()? {
+            if entry?.header().entry_type().is_file() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_all(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("failed to open tar archive {:?}", self.path))?;
+        let mut archive = tar::Archive::new(file);
+
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let mut buf = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut buf)?;
+            entries.push((name, buf));
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn sniff_bytes(name: &str, bytes: &[u8]) -> Result<ArchiveKind> {
+        let path = std::env::temp_dir().join(format!("zipimage-resizer-sniff-{name}"));
+        let mut file = File::create(&path)?;
+        file.write_all(bytes)?;
+        drop(file);
+        let result = ArchiveKind::sniff(&path);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn test_sniff_zip() {
+        assert_eq!(
+            sniff_bytes("zip", b"PK\x03\x04rest of file").unwrap(),
+            ArchiveKind::Zip
+        );
+    }
+
+    #[test]
+    fn test_sniff_rar() {
+        assert_eq!(
+            sniff_bytes("rar", b"Rar!\x1a\x07\x01\x00rest").unwrap(),
+            ArchiveKind::Rar
+        );
+    }
+
+    #[test]
+    fn test_sniff_sevenzip() {
+        assert_eq!(
+            sniff_bytes("7z", b"7z\xbc\xaf\x27\x1crest of file").unwrap(),
+            ArchiveKind::SevenZip
+        );
+    }
+
+    #[test]
+    fn test_sniff_tar() {
+        let mut header = vec![0u8; 512];
+        header[257..262].copy_from_slice(b"ustar");
+        assert_eq!(sniff_bytes("tar", &header).unwrap(), ArchiveKind::Tar);
+    }
+
+    #[test]
+    fn test_sniff_unrecognized() {
+        assert!(sniff_bytes("unknown", b"not an archive").is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use image::DynamicImage;
+
+/// エントリのバイト列を画像としてデコードする。
+///
+/// `image` クレートが標準でデコードできる形式はそのまま委譲し、HEIF/AVIF
+/// (iPhoneで撮影された`.heic`など)はこのモジュールの専用デコーダへ、SVGは
+/// `min_height`に合わせてラスタライズする。いずれの方法でもデコードできない
+/// 場合は`None`を返し、呼び出し側でそのままパススルーできるようにする。
+pub fn decode_image(filename: &str, data: &[u8], min_height: u32) -> Option<DynamicImage> {
+    if is_svg(filename, data) {
+        return rasterize_svg(data, min_height);
+    }
+
+    if let Ok(image) = image::load_from_memory(data) {
+        return Some(image);
+    }
+
+    decode_heif(data)
+}
+
+fn is_svg(filename: &str, data: &[u8]) -> bool {
+    let has_svg_extension = Path::new(filename)
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("svg"))
+        .unwrap_or(false);
+
+    has_svg_extension || looks_like_svg(data)
+}
+
+/// 拡張子が失われている場合に備えて、先頭付近に`<svg`タグが現れるかで判定する
+fn looks_like_svg(data: &[u8]) -> bool {
+    let head_len = data.len().min(512);
+    String::from_utf8_lossy(&data[..head_len])
+        .to_lowercase()
+        .contains("<svg")
+}
+
+/// libheifでHEIF/AVIF画像をデコードする。対応していない形式やデコードに
+/// 失敗した場合は`None`を返す。
+fn decode_heif(data: &[u8]) -> Option<DynamicImage> {
+    use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
+
+    let ctx = HeifContext::read_from_bytes(data).ok()?;
+    let handle = ctx.primary_image_handle().ok()?;
+    let image = handle
+        .decode(ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .ok()?;
+
+    let width = image.width();
+    let height = image.height();
+    let plane = image.planes().interleaved?;
+    let buf = image::RgbImage::from_raw(width, height, plane.data.to_vec())?;
+
+    Some(DynamicImage::ImageRgb8(buf))
+}
+
+/// SVGを`min_height`の高さに合わせてラスタライズする。ドキュメント本来の
+/// サイズではなく、リサイズ後に必要となる解像度で直接描画することで
+/// 余計な再サンプリングを避ける。
+fn rasterize_svg(data: &[u8], min_height: u32) -> Option<DynamicImage> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).ok()?;
+    let doc_size = tree.size();
+
+    let scale = min_height as f32 / doc_size.height();
+    let width = (doc_size.width() * scale).round().max(1.0) as u32;
+    let height = min_height.max(1);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    let buf = image::RgbaImage::from_raw(width, height, pixmap.data().to_vec())?;
+    Some(DynamicImage::ImageRgba8(buf))
+}
@@ -0,0 +1,163 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+/// 出力先アーカイブに書き込む画像の形式
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    /// サポートしている全形式
+    pub const ALL: &'static [OutputFormat] = &[
+        OutputFormat::Webp,
+        OutputFormat::Avif,
+        OutputFormat::Jpeg,
+        OutputFormat::Png,
+    ];
+
+    /// アーカイブに書き込む際の拡張子
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    /// HTTPレスポンスなどで使うMIMEタイプ
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+
+    /// `image::guess_format` が返す形式のうち、この `OutputFormat` で既に
+    /// エンコード済みとみなせるもの
+    pub fn matches(self, format: image::ImageFormat) -> bool {
+        matches!(
+            (self, format),
+            (OutputFormat::Webp, image::ImageFormat::WebP)
+                | (OutputFormat::Avif, image::ImageFormat::Avif)
+                | (OutputFormat::Jpeg, image::ImageFormat::Jpeg)
+                | (OutputFormat::Png, image::ImageFormat::Png)
+        )
+    }
+
+    /// `max_bytes` に収まるようにエンコードする。品質調整が効かない形式
+    /// (PNGなど)では、予算を超えていても最良の結果をそのまま返す。
+    pub fn encode_under_size(self, image: &DynamicImage, max_bytes: usize) -> Result<Vec<u8>> {
+        match self {
+            OutputFormat::Webp => encode_webp_under_size(image, max_bytes),
+            OutputFormat::Avif => encode_avif_under_size(image, max_bytes),
+            OutputFormat::Jpeg => encode_jpeg_under_size(image, max_bytes),
+            OutputFormat::Png => encode_png(image),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // `--format` に指定する名前 (JPEGなら"jpeg")を表示する
+        let value = clap::ValueEnum::to_possible_value(self).expect("no variant is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+const QUALITIES: std::ops::RangeInclusive<u8> = 15..=95;
+
+fn encode_webp_under_size(image: &DynamicImage, max_bytes: usize) -> Result<Vec<u8>> {
+    let image = match image {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+            std::borrow::Cow::Borrowed(image)
+        }
+        _ => std::borrow::Cow::Owned(DynamicImage::ImageRgb8(image.to_rgb8())),
+    };
+
+    let encoder = match webp::Encoder::from_image(&image) {
+        Ok(encoder) => encoder,
+        Err(e) => anyhow::bail!("failed to create webp encoder: {e}"),
+    };
+
+    let webp = QUALITIES
+        .rev()
+        .step_by(10)
+        .map(|quality| encoder.encode(quality as f32))
+        .find(|webp| webp.len() < max_bytes)
+        .unwrap_or_else(|| encoder.encode(10.0));
+
+    Ok(webp.to_vec())
+}
+
+fn encode_avif_under_size(image: &DynamicImage, max_bytes: usize) -> Result<Vec<u8>> {
+    let rgb = image.to_rgb8();
+
+    let mut best = None;
+    for quality in QUALITIES.rev().step_by(10) {
+        let mut buf = Vec::new();
+        image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut buf, 6, quality)
+            .write_image(
+                rgb.as_raw(),
+                rgb.width(),
+                rgb.height(),
+                image::ExtendedColorType::Rgb8,
+            )?;
+
+        let fits = buf.len() < max_bytes;
+        best = Some(buf.clone());
+        if fits {
+            return Ok(buf);
+        }
+    }
+
+    best.ok_or_else(|| anyhow::anyhow!("failed to encode avif"))
+}
+
+fn encode_jpeg_under_size(image: &DynamicImage, max_bytes: usize) -> Result<Vec<u8>> {
+    let rgb = image.to_rgb8();
+
+    let mut best = None;
+    for quality in QUALITIES.rev().step_by(10) {
+        let mut buf = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, quality).encode(
+            rgb.as_raw(),
+            rgb.width(),
+            rgb.height(),
+            image::ExtendedColorType::Rgb8,
+        )?;
+
+        let fits = buf.len() < max_bytes;
+        best = Some(buf.clone());
+        if fits {
+            return Ok(buf);
+        }
+    }
+
+    best.ok_or_else(|| anyhow::anyhow!("failed to encode jpeg"))
+}
+
+/// PNGはロスレスなのでバイト数予算に収めることはできない。最良の圧縮設定で
+/// エンコードした結果をそのまま返す。
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    image::codecs::png::PngEncoder::new_with_quality(
+        &mut buf,
+        image::codecs::png::CompressionType::Best,
+        image::codecs::png::FilterType::Adaptive,
+    )
+    .write_image(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color().into(),
+    )?;
+
+    Ok(buf)
+}
@@ -0,0 +1,260 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use lru::LruCache;
+
+use crate::decode;
+use crate::format::OutputFormat;
+use crate::zip_util;
+
+const MAX_ENTRY_BYTES: usize = 1024 * 1024;
+
+#[derive(Parser, Debug)]
+pub struct ServeArgs {
+    /// 配信するzipファイル
+    zipfiles: Vec<PathBuf>,
+
+    /// 待ち受けるポート番号
+    #[clap(short, long, default_value = "8080")]
+    port: u16,
+
+    /// 最小の高さ
+    #[clap(long, default_value = "1800")]
+    min_height: u32,
+
+    /// 変換後の画像形式
+    #[clap(long, value_enum, default_value_t = OutputFormat::Webp)]
+    format: OutputFormat,
+
+    /// 再エンコードした画像をキャッシュするエントリ数
+    #[clap(long, default_value = "128")]
+    cache_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    archive: PathBuf,
+    entry: String,
+    min_height: u32,
+    format: OutputFormat,
+}
+
+type ImageCache = Mutex<LruCache<CacheKey, Vec<u8>>>;
+
+/// 指定されたzipファイル群をHTTP経由でオンデマンドに配信する。
+///
+/// `GET /{archive}/{entry}` を受け取ると、リクエストされたアーカイブから
+/// 該当エントリのみを読み出し、リサイズ・`--format` で指定した形式へエンコード
+/// したうえでレスポンスとして返す。結果は `ImageCache` に蓄えられ、同じ組み合わせ
+/// への再アクセスは再エンコードなしで応答する。
+///
+/// リクエストは1件ごとにスレッドを起こして処理する。重い再エンコードが
+/// 1件詰まっても、同時に来た他のリクエスト (キャッシュヒットを含む)をブロック
+/// しないようにするため。
+pub fn run(args: ServeArgs) -> Result<()> {
+    let cache_size =
+        NonZeroUsize::new(args.cache_size).unwrap_or(NonZeroUsize::new(1).unwrap());
+    let cache: Arc<ImageCache> = Arc::new(Mutex::new(LruCache::new(cache_size)));
+    let args = Arc::new(args);
+
+    let server = tiny_http::Server::http(("0.0.0.0", args.port))
+        .map_err(|e| anyhow::anyhow!("failed to bind to port {}: {e}", args.port))?;
+    println!("serving {} archive(s) on port {}", args.zipfiles.len(), args.port);
+
+    for request in server.incoming_requests() {
+        let args = Arc::clone(&args);
+        let cache = Arc::clone(&cache);
+        std::thread::spawn(move || {
+            if let Err(err) = handle_request(request, &args, &cache) {
+                eprintln!("error handling request: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_request(
+    request: tiny_http::Request,
+    args: &ServeArgs,
+    cache: &ImageCache,
+) -> Result<()> {
+    let (archive_name, entry_name) = match parse_path(request.url()) {
+        Some(parts) => parts,
+        None => return respond(request, 404, Vec::new(), None, args.format),
+    };
+
+    let Some(archive_path) = args
+        .zipfiles
+        .iter()
+        .find(|p| p.file_name().map(|n| n == archive_name.as_str()).unwrap_or(false))
+    else {
+        return respond(request, 404, Vec::new(), None, args.format);
+    };
+
+    let key = CacheKey {
+        archive: archive_path.clone(),
+        entry: entry_name.clone(),
+        min_height: args.min_height,
+        format: args.format,
+    };
+
+    let body = {
+        let mut cache = cache.lock().unwrap();
+        cache.get(&key).cloned()
+    };
+
+    let body = match body {
+        Some(body) => body,
+        None => {
+            let body = render_entry(archive_path, &entry_name, args.min_height, args.format)?;
+            cache.lock().unwrap().put(key, body.clone());
+            body
+        }
+    };
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case("Range"))
+        .map(|h| h.value.as_str().to_owned());
+
+    match range.as_deref().and_then(parse_range) {
+        Some((start, end)) if start < body.len() => {
+            let end = end.min(body.len() - 1);
+            respond(
+                request,
+                206,
+                body[start..=end].to_vec(),
+                Some((start, end, body.len())),
+                args.format,
+            )
+        }
+        _ => respond(request, 200, body, None, args.format),
+    }
+}
+
+fn parse_path(url: &str) -> Option<(String, String)> {
+    let path = url.split('?').next().unwrap_or(url);
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let archive = percent_decode(segments.next()?);
+    let entry = percent_decode(segments.next()?);
+    if archive.is_empty() || entry.is_empty() {
+        return None;
+    }
+    Some((archive, entry))
+}
+
+/// URLパスセグメントの`%XX`エスケープをデコードする。スキャンされた漫画/コミック
+/// アーカイブではエントリ名に空白や非ASCII文字が含まれるのが普通なので、
+/// アーカイブ/エントリ名として使う前にデコードしておく必要がある。
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// `bytes=start-end` 形式のRangeヘッダーを解釈する。
+fn parse_range(header: &str) -> Option<(usize, usize)> {
+    let spec = header.strip_prefix("bytes=")?;
+    let (start, end) = spec.split_once('-')?;
+    let start = start.parse::<usize>().ok()?;
+    let end = end.parse::<usize>().ok();
+    Some((start, end.unwrap_or(usize::MAX)))
+}
+
+fn render_entry(
+    archive_path: &std::path::Path,
+    entry_name: &str,
+    min_height: u32,
+    format: OutputFormat,
+) -> Result<Vec<u8>> {
+    let bytes = zip_util::read_entry(archive_path, entry_name, None)
+        .with_context(|| format!("failed to read {entry_name:?} from {archive_path:?}"))?;
+
+    let image = decode::decode_image(entry_name, &bytes, min_height)
+        .ok_or_else(|| anyhow::anyhow!("failed to decode {entry_name:?}"))?;
+    let reduced = crate::app::ReduceSize::reduce_size(image, min_height);
+
+    format.encode_under_size(&reduced, MAX_ENTRY_BYTES)
+}
+
+fn respond(
+    request: tiny_http::Request,
+    status: u16,
+    body: Vec<u8>,
+    content_range: Option<(usize, usize, usize)>,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut response = tiny_http::Response::from_data(body)
+        .with_status_code(tiny_http::StatusCode(status))
+        .with_header(header("Content-Type", format.content_type()))
+        .with_header(header("Accept-Ranges", "bytes"));
+
+    if let Some((start, end, total)) = content_range {
+        response =
+            response.with_header(header("Content-Range", &format!("bytes {start}-{end}/{total}")));
+    }
+
+    request.respond(response)?;
+    Ok(())
+}
+
+fn header(field: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(field.as_bytes(), value.as_bytes())
+        .expect("header name/value must be valid ASCII")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range_closed() {
+        assert_eq!(parse_range("bytes=0-499"), Some((0, 499)));
+    }
+
+    #[test]
+    fn test_parse_range_open_ended() {
+        assert_eq!(parse_range("bytes=500-"), Some((500, usize::MAX)));
+    }
+
+    #[test]
+    fn test_parse_range_missing_prefix() {
+        assert_eq!(parse_range("0-499"), None);
+    }
+
+    #[test]
+    fn test_parse_range_non_numeric() {
+        assert_eq!(parse_range("bytes=abc-def"), None);
+    }
+
+    #[test]
+    fn test_parse_path_decodes_percent_escapes() {
+        assert_eq!(
+            parse_path("/book.zip/001%20cover.jpg"),
+            Some(("book.zip".to_owned(), "001 cover.jpg".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_path_missing_entry() {
+        assert_eq!(parse_path("/book.zip"), None);
+    }
+}
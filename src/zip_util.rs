@@ -1,103 +1,305 @@
 use std::fs::File;
 use std::path::Path;
 
+use anyhow::{Context, Result};
+use async_zip::base::read::seek::ZipFileReader;
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use futures::stream::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+
 /// zipファイルに含まれるファイル数を取得する
+///
+/// `password` が指定された場合は先頭エントリの復号を試み、パスワードの
+/// 誤りを早期に検出する。
+///
 /// # Arguments
 /// * `zip_file` - ファイル数を取得するzipファイル
+/// * `password` - 暗号化されたzipを読む場合のパスワード
 /// # Returns
 /// ファイル数
 /// # Errors
-/// zipファイルが存在しない場合、またはzipファイルが壊れている場合にエラーを返す
-pub fn get_file_count<P: AsRef<Path>>(zip_file: P) -> zip::result::ZipResult<usize> {
+/// zipファイルが存在しない場合、zipファイルが壊れている場合、または
+/// パスワードが誤っている場合にエラーを返す
+pub fn get_file_count<P: AsRef<Path>>(zip_file: P, password: Option<&str>) -> Result<usize> {
     let file = File::open(zip_file)?;
-    let archive = zip::ZipArchive::new(file)?;
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    if let Some(password) = password {
+        if archive.len() > 0 {
+            archive
+                .by_index_decrypt(0, password.as_bytes())?
+                .map_err(|_| anyhow::anyhow!("incorrect password"))?;
+        }
+    }
 
     Ok(archive.len())
 }
 
-/// zipファイルを解凍する
+/// zipファイル内の単一のエントリのみを読み出す
 ///
-/// # Arguments
+/// アーカイブ全体を展開せず、指定したエントリだけを取り出したい場合に使う。
 ///
-/// * `zip_file` - 解凍するzipファイル
-/// * `output_dir` - 解凍先のディレクトリ
-pub fn unzip<P1: AsRef<Path>, P2: AsRef<Path>>(
-    zip_file: P1,
-    output_dir: P2,
-) -> zip::result::ZipResult<()> {
+/// # Arguments
+/// * `zip_file` - 読み出し元のzipファイル
+/// * `entry_name` - 読み出すエントリ名
+/// * `password` - 暗号化されたzipを読む場合のパスワード
+pub fn read_entry<P: AsRef<Path>>(
+    zip_file: P,
+    entry_name: &str,
+    password: Option<&str>,
+) -> Result<Vec<u8>> {
     let file = File::open(zip_file)?;
     let mut archive = zip::ZipArchive::new(file)?;
+    let mut entry = match password {
+        Some(password) => archive
+            .by_name_decrypt(entry_name, password.as_bytes())?
+            .map_err(|_| anyhow::anyhow!("incorrect password"))?,
+        None => archive.by_name(entry_name)?,
+    };
 
-    archive.extract(output_dir)
+    let mut buf = Vec::with_capacity(entry.size() as usize);
+    std::io::Read::read_to_end(&mut entry, &mut buf)?;
+
+    Ok(buf)
 }
 
-fn get_options(path: &Path) -> zip::write::FileOptions {
-    let ext = path
-        .extension()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_lowercase();
+/// 平文のzipを読み直し、全エントリをAES-256暗号化しながら新しいzipとして書き出す
+///
+/// `stream_entries` が書き出す中間ファイルは平文なので、暗号化出力が
+/// 要求された場合はこの関数で最終的な暗号化zipに変換する。エントリの圧縮方式は
+/// `stream_entries` の時点で決定済みのものをそのまま引き継ぐ。
+///
+/// # Arguments
+/// * `src_zipfile` - 変換元の平文zipファイル
+/// * `dst_file` - 書き込み先のファイル
+/// * `password` - 出力zipの暗号化パスワード
+/// * `compression_level` - 圧縮レベル (対応する圧縮方式でのみ有効)
+pub fn encrypt_zip<P: AsRef<Path>>(
+    src_zipfile: P,
+    dst_file: &File,
+    password: &str,
+    compression_level: Option<i32>,
+) -> Result<()> {
+    let src = File::open(src_zipfile)?;
+    let mut archive = zip::ZipArchive::new(src)?;
+    let mut writer = zip::ZipWriter::new(dst_file);
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let options = zip::write::FileOptions::default()
+            .compression_method(entry.compression())
+            .compression_level(compression_level.map(i64::from))
+            .with_aes_encryption(zip::AesMode::Aes256, password);
+
+        writer.start_file(entry.name().to_owned(), options)?;
+        std::io::copy(&mut entry, &mut writer)?;
+    }
+
+    writer.finish()?;
 
-    match ext.as_str() {
-        "jpg" | "jpeg" | "png" | "webp" => {
-            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored)
+    Ok(())
+}
+
+/// 出力zipのエントリに使う圧縮方式
+#[derive(Debug, Clone, Copy)]
+pub struct OutputCompression {
+    pub method: Compression,
+    pub level: Option<i32>,
+}
+
+impl OutputCompression {
+    fn stored() -> Self {
+        Self {
+            method: Compression::Stored,
+            level: None,
         }
-        _ => zip::write::FileOptions::default(),
     }
 }
 
-fn relative_path<'a>(path: &'a Path, base_dir: &Path) -> std::borrow::Cow<'a, str> {
-    path.strip_prefix(base_dir).unwrap().to_string_lossy()
+/// 既に圧縮済みの画像形式の拡張子かどうか。再圧縮しても縮まらないため、
+/// 出力アーカイブへは常にStoredで書き込む。
+pub fn is_image_extension(name: &str) -> bool {
+    matches!(
+        Path::new(name)
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase()
+            .as_str(),
+        "jpg" | "jpeg" | "png" | "webp" | "avif"
+    )
 }
 
-fn zip_process_dir<W, P>(zw: &mut zip::ZipWriter<W>, dir: P, base_dir: &Path) -> std::io::Result<()>
+/// エントリに書き込む圧縮方式を決める。画像エントリは再圧縮の恩恵がないため
+/// `default` (`--compression`で指定された方式)を無視して常にStoredにする。
+fn entry_compression(entry_name: &str, default: OutputCompression) -> OutputCompression {
+    if is_image_extension(entry_name) {
+        OutputCompression::stored()
+    } else {
+        default
+    }
+}
+
+/// `transform` がエントリに対して返す処理結果
+pub enum EntryOutcome {
+    /// 加工後のバイト列に差し替えて書き込む。拡張子が変わる場合に備えて
+    /// エントリ名も併せて指定する。
+    Replace { filename: String, bytes: Vec<u8> },
+    /// 元のバイト列のまま書き込む。圧縮方式は`Replace`と同様に
+    /// `entry_compression`で決まる (画像ならStored、それ以外は`output_compression`)
+    PassThrough(Vec<u8>),
+}
+
+/// ソースzipのエントリを読み込んでは `transform` に渡し、結果を `dst` へ
+/// 都度書き込む。作業ディレクトリへの展開を経由しないため、ディスク上の
+/// ピーク使用量はおおむねエントリ1件分にとどまる。
+///
+/// エントリの読み込みと `transform` の呼び出しは1つの処理単位として
+/// `concurrency` 件までしか同時に走らせない。読み込みだけを先に済ませてしまうと
+/// 結局アーカイブ全体をメモリに載せることになるため、どちらも同じ同時実行数の
+/// 枠の中で行う。
+///
+/// `transform` はエントリ名と元のバイト列を受け取り、書き込むべき内容
+/// ([`EntryOutcome`]) を返す非同期処理である。結果はソースと同じ順序で
+/// `dst` に書き込まれる。
+///
+/// # Arguments
+/// * `src_zipfile` - 変換元のzipファイル
+/// * `dst` - 書き込み先 (zipとしてシークしながら書き込めるもの)
+/// * `concurrency` - 同時に処理する (読み込み+`transform`の)エントリ数
+/// * `password` - 暗号化されたソースzipを読む場合のパスワード
+/// * `output_compression` - 画像以外のエントリに適用する圧縮方式
+/// * `transform` - エントリごとの変換処理
+///
+/// # Returns
+/// [`EntryOutcome::Replace`] で書き込んだエントリの数
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_entries<P, W, F, Fut>(
+    src_zipfile: P,
+    dst: W,
+    concurrency: usize,
+    password: Option<String>,
+    output_compression: OutputCompression,
+    transform: F,
+) -> Result<usize>
 where
-    W: std::io::Write + std::io::Seek,
     P: AsRef<Path>,
+    W: AsyncWrite + Unpin,
+    F: Fn(String, Vec<u8>) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<EntryOutcome>> + Send + 'static,
 {
-    let entries = walkdir::WalkDir::new(&dir)
-        .max_depth(1)
-        .sort_by_file_name()
-        .into_iter()
-        .flatten()
-        .collect::<Vec<_>>();
+    let src_path = src_zipfile.as_ref().to_path_buf();
+    let file = tokio::fs::File::open(&src_path).await?;
+    let reader = ZipFileReader::with_tokio(file).await?;
+    let entry_count = reader.file().entries().len();
 
-    for file in entries.iter().filter(|e| e.file_type().is_file()) {
-        let path = file.path();
-        let filepath = relative_path(path, base_dir);
+    let reader = std::sync::Arc::new(Mutex::new(reader));
+    let transform = std::sync::Arc::new(transform);
+    let password = std::sync::Arc::new(password);
+    let mut writer = ZipFileWriter::with_tokio(dst);
 
-        println!("adding {:?} as {:?} ...", path, filepath);
+    let mut results = futures::stream::iter(0..entry_count)
+        .map(|index| {
+            let reader = reader.clone();
+            let transform = transform.clone();
+            let password = password.clone();
+            let src_path = src_path.clone();
+            async move {
+                let (filename, is_dir, uncompressed_size) = {
+                    let reader = reader.lock().await;
+                    let entry = &reader.file().entries()[index];
+                    (
+                        entry.filename().as_str()?.to_owned(),
+                        entry.dir()?,
+                        entry.uncompressed_size(),
+                    )
+                };
 
-        let options = get_options(path);
+                if is_dir {
+                    return Ok(None);
+                }
 
-        let mut file = std::fs::File::open(path)?;
-        zw.start_file(filepath, options)?;
-        std::io::copy(&mut file, zw)?;
-    }
+                // async_zipはAES暗号化されたエントリを復号できないため、パスワードが
+                // 指定されている場合はzipクレート経由でその場で読み直す。
+                let raw = match password.as_ref() {
+                    Some(password) => {
+                        let src_path = src_path.clone();
+                        let filename = filename.clone();
+                        let password = password.clone();
+                        tokio::task::spawn_blocking(move || {
+                            read_entry(&src_path, &filename, Some(&password))
+                        })
+                        .await??
+                    }
+                    None => {
+                        let mut reader = reader.lock().await;
+                        let mut entry_reader = reader.reader_with_entry(index).await?;
+                        let mut buf = Vec::with_capacity(uncompressed_size as usize);
+                        entry_reader.read_to_end_checked(&mut buf).await.with_context(|| {
+                            format!(
+                                "failed to read entry {filename:?}; if the archive is password-protected, pass --password"
+                            )
+                        })?;
+                        buf
+                    }
+                };
 
-    for dir_entry in entries
-        .iter()
-        .filter(|e| e.file_type().is_dir() && e.path() != dir.as_ref())
-    {
-        let path = dir_entry.path();
-        let filepath = relative_path(path, base_dir);
-        zw.add_directory(filepath, zip::write::FileOptions::default())?;
-        zip_process_dir(zw, path, base_dir)?;
+                let outcome = transform(filename.clone(), raw).await?;
+                Ok(Some((filename, outcome)))
+            }
+        })
+        .buffered(concurrency.max(1));
+
+    let mut replaced_count = 0;
+    while let Some(result) = results.next().await {
+        let Some((filename, outcome)) = result? else {
+            continue;
+        };
+
+        let (filename, bytes) = match outcome {
+            EntryOutcome::Replace { filename, bytes } => {
+                replaced_count += 1;
+                (filename, bytes)
+            }
+            EntryOutcome::PassThrough(bytes) => (filename, bytes),
+        };
+        let compression = entry_compression(&filename, output_compression);
+
+        let mut builder = ZipEntryBuilder::new(filename.into(), compression.method);
+        if let Some(level) = compression.level {
+            builder = builder.compression_level(level);
+        }
+        writer.write_entry_whole(builder, &bytes).await?;
     }
 
-    Ok(())
-}
+    writer.close().await?;
 
-/// 指定したディレクトリをzipファイルに圧縮する
-/// # Arguments
-/// * `src_dir` - 圧縮するディレクトリ
-/// * `dst_file` - 圧縮先のzipファイル
-pub fn zip<P1: AsRef<Path>>(src_dir: P1, dst_file: &File) -> std::io::Result<()> {
-    let mut zw = zip::ZipWriter::new(dst_file);
+    Ok(replaced_count)
+}
 
-    zip_process_dir(&mut zw, src_dir.as_ref(), src_dir.as_ref())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    zw.finish()?;
+    #[test]
+    fn test_entry_compression_image_is_always_stored() {
+        let default = OutputCompression {
+            method: Compression::Zstd,
+            level: None,
+        };
+        let compression = entry_compression("page001.webp", default);
+        assert!(matches!(compression.method, Compression::Stored));
+    }
 
-    Ok(())
+    #[test]
+    fn test_entry_compression_non_image_uses_output_compression() {
+        let default = OutputCompression {
+            method: Compression::Zstd,
+            level: None,
+        };
+        let compression = entry_compression("ComicInfo.xml", default);
+        assert!(matches!(compression.method, Compression::Zstd));
+    }
 }